@@ -0,0 +1,167 @@
+//! A generic adapter that makes any `StateMachine` idempotent against replayed transitions,
+//! the way a bank tracks the last N transaction ids so a resubmitted transaction is a no-op.
+
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use super::StateMachine;
+
+/// How many recent transitions `Deduplicated` remembers before it starts forgetting them.
+const MAX_SEEN: usize = 1024;
+
+/// Wraps a `StateMachine` `M` so that re-applying a transition it has seen within the last
+/// `MAX_SEEN` transitions is a no-op instead of being delegated to `M`.
+pub struct Deduplicated<M>(PhantomData<M>);
+
+/// The state of a `Deduplicated<M>` machine: `M`'s own state, plus a ring buffer of the
+/// hashes of the most recently applied transitions.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DeduplicatedState<S> {
+	inner: S,
+	seen: VecDeque<u64>,
+}
+
+impl<S> DeduplicatedState<S> {
+	/// Wrap a fresh inner state that hasn't seen any transitions yet.
+	pub fn new(inner: S) -> Self {
+		Self { inner, seen: VecDeque::new() }
+	}
+
+	/// The wrapped machine's current state.
+	pub fn inner(&self) -> &S {
+		&self.inner
+	}
+}
+
+impl<M: StateMachine> StateMachine for Deduplicated<M>
+where
+	M::Transition: Hash,
+	M::State: Clone,
+{
+	type State = DeduplicatedState<M::State>;
+	type Transition = M::Transition;
+
+	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+		let transition_hash = crate::hash(t);
+
+		if starting_state.seen.contains(&transition_hash) {
+			return starting_state.clone();
+		}
+
+		let mut seen = starting_state.seen.clone();
+		if seen.len() == MAX_SEEN {
+			seen.pop_front();
+		}
+		seen.push_back(transition_hash);
+
+		DeduplicatedState { inner: M::next_state(&starting_state.inner, t), seen }
+	}
+}
+
+#[cfg(test)]
+struct Adder;
+
+#[cfg(test)]
+impl StateMachine for Adder {
+	type State = u64;
+	type Transition = u64;
+
+	fn next_state(starting_state: &u64, t: &u64) -> u64 {
+		starting_state + t
+	}
+}
+
+#[test]
+fn dedup_first_application_goes_through() {
+	let start = DeduplicatedState::new(0u64);
+	let end = Deduplicated::<Adder>::next_state(&start, &5);
+
+	assert_eq!(*end.inner(), 5);
+}
+
+#[test]
+fn dedup_repeated_transition_inside_window_is_a_no_op() {
+	let start = DeduplicatedState::new(0u64);
+	let after_first = Deduplicated::<Adder>::next_state(&start, &5);
+	let after_repeat = Deduplicated::<Adder>::next_state(&after_first, &5);
+
+	// The second application of the same transition is ignored: the state is untouched.
+	assert_eq!(after_repeat, after_first);
+	assert_eq!(*after_repeat.inner(), 5);
+}
+
+#[test]
+fn dedup_distinct_transitions_both_apply() {
+	let start = DeduplicatedState::new(0u64);
+	let after_first = Deduplicated::<Adder>::next_state(&start, &5);
+	let after_second = Deduplicated::<Adder>::next_state(&after_first, &7);
+
+	assert_eq!(*after_second.inner(), 12);
+}
+
+#[test]
+fn dedup_transition_reapplies_once_the_window_has_slid_past_it() {
+	let mut state = DeduplicatedState::new(0u64);
+	state = Deduplicated::<Adder>::next_state(&state, &1);
+
+	// Push MAX_SEEN more distinct transitions through, which evicts the hash of `1`
+	// from the ring buffer.
+	for t in 2..=(MAX_SEEN as u64 + 1) {
+		state = Deduplicated::<Adder>::next_state(&state, &t);
+	}
+
+	let before = *state.inner();
+	let state = Deduplicated::<Adder>::next_state(&state, &1);
+
+	// `1` is no longer in the window, so it is re-applied instead of being a no-op.
+	assert_eq!(*state.inner(), before + 1);
+}
+
+#[test]
+fn dedup_repeated_atm_withdrawal_inside_window_is_a_no_op() {
+	use super::p3_atm::{Action, Atm, Key};
+
+	let atm = Atm::authenticated_with_balance(1234, 10, 100);
+	let withdraw_one = Action::PressKey(Key::One);
+
+	let start = DeduplicatedState::new(atm);
+	let after_first = Deduplicated::<Atm>::next_state(&start, &withdraw_one);
+	let after_repeat = Deduplicated::<Atm>::next_state(&after_first, &withdraw_one);
+
+	// The repeated `PressKey(One)` is a no-op: it's the exact same transition seen moments ago.
+	assert_eq!(after_repeat, after_first);
+}
+
+/// A distinct, valid `VerifyingKey` for every `seed`, so a loop can generate as many
+/// unique filler transitions as it needs.
+fn verifying_key_for(seed: u64) -> ed25519_dalek::VerifyingKey {
+	let mut bytes = [0u8; 32];
+	bytes[..8].copy_from_slice(&seed.to_le_bytes());
+	ed25519_dalek::SigningKey::from_bytes(&bytes).verifying_key()
+}
+
+#[test]
+fn dedup_atm_withdrawal_reapplies_once_the_window_has_slid_past_it() {
+	use super::p3_atm::{Action, Atm, Key};
+
+	let atm = Atm::authenticated_with_balance(1234, 1100, 1100);
+	let withdraw_one = Action::PressKey(Key::One);
+
+	let mut state = DeduplicatedState::new(atm);
+	state = Deduplicated::<Atm>::next_state(&state, &withdraw_one);
+
+	// Push MAX_SEEN distinct filler transitions through (ignored by the ATM itself,
+	// since it isn't `Waiting`, but each still occupies a distinct slot in the ring
+	// buffer), which evicts the hash of `withdraw_one` from the window.
+	for seed in 0..(MAX_SEEN as u64) {
+		state = Deduplicated::<Atm>::next_state(&state, &Action::SwipeCard(verifying_key_for(seed)));
+	}
+
+	let before = state.inner().clone();
+	let state = Deduplicated::<Atm>::next_state(&state, &withdraw_one);
+
+	// `withdraw_one` is no longer in the window, so it is re-applied instead of being a
+	// no-op: the register now holds the key that was pressed.
+	assert_ne!(*state.inner(), before);
+}
@@ -0,0 +1,19 @@
+//! State machines let you express a system as a set of states together with the transitions
+//! that move it from one state to the next. This module collects a handful of state machine
+//! exercises, plus some reusable adapters over the `StateMachine` trait itself.
+
+/// A state machine is defined by the state it operates over and the transitions it accepts.
+/// Applying a transition to a state yields a brand new state; the old state is not kept around.
+pub trait StateMachine {
+	/// The type of state used by this machine.
+	type State;
+	/// The type of transitions used by this machine.
+	type Transition;
+
+	/// Use the given transition to compute the next state from the given state.
+	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State;
+}
+
+mod p3_atm;
+mod p4_payment_channel;
+pub mod deduplicated;
@@ -1,8 +1,11 @@
-//! The automated teller machine gives you cash after you swipe your card and enter your pin.
-//! The atm may fail to give you cash if it is empty or you haven't swiped your card, or you have
-//! entered the wrong pin.
+//! The automated teller machine gives you cash after you swipe your card and prove you hold the
+//! matching private key. The atm may fail to give you cash if it is empty, you haven't swiped
+//! your card, you failed the challenge, or your account doesn't hold enough funds.
+
+use std::collections::HashMap;
+
+use ed25519_dalek::{Signature, VerifyingKey};
 
-use crate::c1_state_machine::p3_atm::Auth::Waiting;
 use super::StateMachine;
 
 /// The keys on the ATM keypad
@@ -17,43 +20,100 @@ pub enum Key {
 
 /// Something you can do to the ATM
 pub enum Action {
-	/// Swipe your card at the ATM. The attached value is the hash of the pin
-	/// that should be keyed in on the keypad next.
-	SwipeCard(u64),
+	/// Swipe your card at the ATM. The attached value is the public key that
+	/// the ATM will challenge next.
+	SwipeCard(VerifyingKey),
 	/// Press a key on the keypad
 	PressKey(Key),
+	/// Answer the ATM's challenge with a signature over its nonce, proving
+	/// ownership of the swiped public key's private key.
+	SubmitSignature(Signature),
+	/// Advance the ATM's internal clock by one tick. This is the only action
+	/// that makes progress while the machine is locked out.
+	Tick,
+}
+
+// `VerifyingKey` and `Signature` don't derive `Hash`, so `Action` can't either. Hash over
+// each variant's byte representation instead, which is all `Deduplicated` needs to tell
+// transitions apart.
+impl std::hash::Hash for Action {
+	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+		match self {
+			Action::SwipeCard(pubkey) => {
+				0u8.hash(state);
+				pubkey.to_bytes().hash(state);
+			},
+			Action::PressKey(key) => {
+				1u8.hash(state);
+				key.hash(state);
+			},
+			Action::SubmitSignature(sig) => {
+				2u8.hash(state);
+				sig.to_bytes().hash(state);
+			},
+			Action::Tick => 3u8.hash(state),
+		}
+	}
 }
 
+/// The lockout doubles with every consecutive failed challenge: the first failure
+/// locks the machine for `INITIAL_LOCKOUT.pow(0)` ticks, the second for
+/// `INITIAL_LOCKOUT.pow(1)`, and so on.
+const INITIAL_LOCKOUT: u64 = 2;
+
 /// The various states of authentication possible with the ATM
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum Auth {
 	/// No session has begun yet. Waiting for the user to swipe their card
 	Waiting,
-	/// The user has swiped their card, providing the enclosed PIN hash.
-	/// Waiting for the user to key in their pin
-	Authenticating(u64),
-	/// The user has authenticated. Waiting for them to key in the amount
-	/// of cash to withdraw
-	Authenticated,
+	/// The user has swiped their card. The enclosed nonce was issued as a
+	/// challenge to the enclosed public key; waiting for a matching signature.
+	Challenged(u64, VerifyingKey),
+	/// The user has authenticated, under the account identified by the enclosed
+	/// account id. Waiting for them to key in the amount of cash to withdraw
+	Authenticated(u64),
 }
 
-/// The ATM. When a card is swiped, the ATM learns the correct pin's hash.
-/// It waits for you to key in your pin. You can press as many numeric keys as
-/// you like followed by enter. If the pin is incorrect, your card is returned
-/// and the ATM automatically goes back to the main menu. If your pin is correct,
-/// the ATM waits for you to key in an amount of money to withdraw. Withdraws
-/// are bounded only by the cash in the machine (there is no account balance).
+/// The ATM. When a card is swiped, the ATM issues a nonce challenging the enclosed public
+/// key. If the matching signature comes back, the ATM waits for you to key in an amount of
+/// money to withdraw. Withdraws are bounded by whichever is smaller: the cash in the machine,
+/// or the balance of the account behind the swiped card.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Atm {
 	/// How much money is in the ATM
 	cash_inside: u64,
 	/// The machine's authentication status.
-	expected_pin_hash: Auth,
+	auth: Auth,
 	/// All the keys that have been pressed since the last `Enter`
 	keystroke_register: Vec<Key>,
+	/// Per-account balances, keyed by the account id (the hash of the account's public key)
+	balances: HashMap<u64, u64>,
+	/// The number of consecutive failed challenges since the last successful one
+	failed_attempts: u32,
+	/// The machine ignores everything except `Action::Tick` until `current_tick`
+	/// reaches this value
+	locked_until: u64,
+	/// The ATM's internal clock, advanced by `Action::Tick`
+	current_tick: u64,
 }
 
 impl Atm {
+	/// Build an ATM that is already authenticated as `account`, with `balance` on that
+	/// account. Only needed so other modules (e.g. `Deduplicated`'s tests) can exercise
+	/// the withdrawal transition without driving the full challenge-response handshake.
+	#[cfg(test)]
+	pub(crate) fn authenticated_with_balance(account: u64, balance: u64, cash_inside: u64) -> Self {
+		Self {
+			cash_inside,
+			auth: Auth::Authenticated(account),
+			keystroke_register: Vec::new(),
+			balances: HashMap::from([(account, balance)]),
+			failed_attempts: 0,
+			locked_until: 0,
+			current_tick: 0,
+		}
+	}
+
 	fn add_key_to_register(&self, key: &Key) -> Self {
 		let mut new_state = self.clone();
 		new_state.keystroke_register.push(key.clone());
@@ -64,14 +124,24 @@ impl Atm {
 		self.keystroke_register.clear();
 	}
 
-	fn is_correct_pin(&self, pin_hash: &u64) -> bool {
-		&crate::hash(&self.keystroke_register) == pin_hash
+	fn reset_auth(&mut self) {
+		self.auth = Auth::Waiting
+	}
+
+	fn balance_of(&self, account: u64) -> u64 {
+		*self.balances.get(&account).unwrap_or(&0)
 	}
 
-	fn reset_expected_pin_hash(&mut self) {
-		self.expected_pin_hash = Auth::Waiting
+	/// A nonce that looks random but is fully determined by the ATM's clock and the
+	/// public key being challenged, so the machine never needs real randomness.
+	fn generate_nonce(&self, pubkey: &VerifyingKey) -> u64 {
+		crate::hash(&(self.current_tick, pubkey.to_bytes()))
 	}
+}
 
+/// The account id under which a public key's balance is tracked.
+fn account_id(pubkey: &VerifyingKey) -> u64 {
+	crate::hash(&pubkey.to_bytes())
 }
 
 impl StateMachine for Atm {
@@ -84,49 +154,67 @@ impl StateMachine for Atm {
 		use Action::*;
 		let same_state = starting_state.clone();
 
-		match starting_state.expected_pin_hash {
+		if let Tick = t {
+			let mut new_state = same_state;
+			new_state.current_tick += 1;
+			return new_state;
+		}
+
+		if starting_state.current_tick < starting_state.locked_until {
+			return same_state;
+		}
+
+		match &starting_state.auth {
 			Auth::Waiting => match t {
-				SwipeCard(code_hash) => Self {
-					expected_pin_hash: Auth::Authenticating(*code_hash),
-					..same_state
+				SwipeCard(pubkey) => {
+					let nonce = starting_state.generate_nonce(pubkey);
+					Self { auth: Auth::Challenged(nonce, *pubkey), ..same_state }
 				},
 				_ => same_state,
 			},
-			Auth::Authenticating(pin_hash) => match t {
-				PressKey(Key::Enter) => {
-					let pin_correct = starting_state.is_correct_pin(&pin_hash);
+			Auth::Challenged(nonce, pubkey) => match t {
+				SubmitSignature(sig) => {
 					let mut new_state = same_state;
-					new_state.reset_keystroke_register();
-					new_state.reset_expected_pin_hash();
-					if pin_correct {
-						new_state.expected_pin_hash = Auth::Authenticated;
+					if crate::verify_signature(pubkey, &nonce.to_le_bytes(), sig) {
+						new_state.auth = Auth::Authenticated(account_id(pubkey));
+						new_state.failed_attempts = 0;
+					} else {
+						let penalty = INITIAL_LOCKOUT.checked_pow(starting_state.failed_attempts)
+							.unwrap_or(u64::MAX);
+						new_state.locked_until =
+							starting_state.current_tick.saturating_add(penalty);
+						new_state.failed_attempts += 1;
+						new_state.reset_auth();
 					}
 					new_state
 				},
-				PressKey(key) => starting_state.add_key_to_register(key),
-				_ => same_state
+				_ => same_state,
 			},
-			Auth::Authenticated => match t {
-				PressKey(Key::Enter) => {
-					let mut new_state = same_state;
-					let amount_to_withdraw = <u64 as FromKeyVec>::from(&new_state.keystroke_register);
-					let update_cash = if (amount_to_withdraw > starting_state.cash_inside) {
-						starting_state.cash_inside
-					} else {
-						starting_state.cash_inside - amount_to_withdraw
-					};
-					new_state.cash_inside = update_cash;
-					new_state.reset_keystroke_register();
-					new_state.reset_expected_pin_hash();
-					new_state
+			Auth::Authenticated(account) => {
+				let account = *account;
+				match t {
+					PressKey(Key::Enter) => {
+						let mut new_state = same_state;
+						let amount_to_withdraw =
+							<u64 as FromKeyVec>::from(&new_state.keystroke_register);
+						let withdrawal = amount_to_withdraw
+							.min(starting_state.balance_of(account))
+							.min(starting_state.cash_inside);
+						new_state.cash_inside -= withdrawal;
+						if withdrawal > 0 {
+							new_state
+								.balances
+								.insert(account, starting_state.balance_of(account) - withdrawal);
+						}
+						new_state.reset_keystroke_register();
+						new_state.reset_auth();
+						new_state
+					},
+					PressKey(key) => starting_state.add_key_to_register(key),
+					_ => same_state,
 				}
-				PressKey(key) => starting_state.add_key_to_register(key),
-				_ => same_state
-
-				},
-
-			}
-
+			},
+		}
 	}
 }
 
@@ -151,187 +239,197 @@ impl FromKeyVec for  u64 {
 	}
 }
 
-#[test]
-fn sm_3_simple_swipe_card() {
-	let start =
-		Atm { cash_inside: 10, expected_pin_hash: Auth::Waiting, keystroke_register: Vec::new() };
-	let end = Atm::next_state(&start, &Action::SwipeCard(1234));
-	let expected = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(1234),
-		keystroke_register: Vec::new(),
-	};
+#[cfg(test)]
+use ed25519_dalek::{Signer, SigningKey};
 
-	assert_eq!(end, expected);
+#[cfg(test)]
+fn keypair(seed: u8) -> SigningKey {
+	SigningKey::from_bytes(&[seed; 32])
 }
 
-#[test]
-fn sm_3_swipe_card_again_part_way_through() {
-	let start = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(1234),
-		keystroke_register: Vec::new(),
-	};
-	let end = Atm::next_state(&start, &Action::SwipeCard(1234));
-	let expected = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(1234),
+#[cfg(test)]
+fn fresh_atm(cash_inside: u64, balances: HashMap<u64, u64>) -> Atm {
+	Atm {
+		cash_inside,
+		auth: Auth::Waiting,
 		keystroke_register: Vec::new(),
-	};
+		balances,
+		failed_attempts: 0,
+		locked_until: 0,
+		current_tick: 0,
+	}
+}
 
-	assert_eq!(end, expected);
+#[test]
+fn sm_3_swipe_card_issues_a_challenge() {
+	let signer = keypair(1);
+	let pubkey = signer.verifying_key();
+	let start = fresh_atm(10, HashMap::new());
+	let end = Atm::next_state(&start, &Action::SwipeCard(pubkey));
 
-	let start = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(1234),
-		keystroke_register: vec![Key::One, Key::Three],
-	};
-	let end = Atm::next_state(&start, &Action::SwipeCard(1234));
-	let expected = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(1234),
-		keystroke_register: vec![Key::One, Key::Three],
-	};
+	let nonce = start.generate_nonce(&pubkey);
+	let expected = Atm { auth: Auth::Challenged(nonce, pubkey), ..start };
 
 	assert_eq!(end, expected);
 }
 
 #[test]
 fn sm_3_press_key_before_card_swipe() {
-	let start =
-		Atm { cash_inside: 10, expected_pin_hash: Auth::Waiting, keystroke_register: Vec::new() };
+	let start = fresh_atm(10, HashMap::new());
 	let end = Atm::next_state(&start, &Action::PressKey(Key::One));
-	let expected =
-		Atm { cash_inside: 10, expected_pin_hash: Auth::Waiting, keystroke_register: Vec::new() };
 
-	assert_eq!(end, expected);
+	assert_eq!(end, start);
 }
 
 #[test]
-fn sm_3_enter_single_digit_of_pin() {
-	let start = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(1234),
-		keystroke_register: Vec::new(),
-	};
-	let end = Atm::next_state(&start, &Action::PressKey(Key::One));
-	let expected = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(1234),
-		keystroke_register: vec![Key::One],
+fn sm_3_valid_signature_authenticates() {
+	let signer = keypair(1);
+	let pubkey = signer.verifying_key();
+	let start = fresh_atm(10, HashMap::new());
+	let challenged = Atm::next_state(&start, &Action::SwipeCard(pubkey));
+
+	let nonce = match challenged.auth {
+		Auth::Challenged(nonce, _) => nonce,
+		_ => panic!("expected to be challenged"),
 	};
+	let sig = signer.sign(&nonce.to_le_bytes());
+	let end = Atm::next_state(&challenged, &Action::SubmitSignature(sig));
 
+	let expected = Atm { auth: Auth::Authenticated(account_id(&pubkey)), ..challenged };
 	assert_eq!(end, expected);
-
-	let start = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(1234),
-		keystroke_register: vec![Key::One],
-	};
-	let end1 = Atm::next_state(&start, &Action::PressKey(Key::Two));
-	let expected1 = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(1234),
-		keystroke_register: vec![Key::One, Key::Two],
-	};
-
-	assert_eq!(end1, expected1);
 }
 
 #[test]
-fn sm_3_enter_wrong_pin() {
-	// Create hash of pin
-	let pin = vec![Key::One, Key::Two, Key::Three, Key::Four];
-	let pin_hash = crate::hash(&pin);
-
-	let start = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(pin_hash),
-		keystroke_register: vec![Key::Three, Key::Three, Key::Three, Key::Three],
+fn sm_3_invalid_signature_returns_to_waiting_and_locks_out() {
+	let signer = keypair(1);
+	let impostor = keypair(2);
+	let pubkey = signer.verifying_key();
+	let start = fresh_atm(10, HashMap::new());
+	let challenged = Atm::next_state(&start, &Action::SwipeCard(pubkey));
+
+	let nonce = match challenged.auth {
+		Auth::Challenged(nonce, _) => nonce,
+		_ => panic!("expected to be challenged"),
 	};
-	let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
-	let expected =
-		Atm { cash_inside: 10, expected_pin_hash: Auth::Waiting, keystroke_register: Vec::new() };
+	// Signed by the wrong key - the signature does not match the swiped public key.
+	let sig = impostor.sign(&nonce.to_le_bytes());
+	let end = Atm::next_state(&challenged, &Action::SubmitSignature(sig));
 
+	let expected = Atm {
+		auth: Auth::Waiting,
+		failed_attempts: 1,
+		locked_until: 1,
+		..challenged
+	};
 	assert_eq!(end, expected);
 }
 
 #[test]
-fn sm_3_enter_correct_pin() {
-	// Create hash of pin
-	let pin = vec![Key::One, Key::Two, Key::Three, Key::Four];
-	let pin_hash = crate::hash(&pin);
-
+fn sm_3_withdraw_acceptable_amount() {
+	let signer = keypair(1);
+	let pubkey = signer.verifying_key();
+	let account = account_id(&pubkey);
 	let start = Atm {
 		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticating(pin_hash),
-		keystroke_register: vec![Key::One, Key::Two, Key::Three, Key::Four],
+		auth: Auth::Authenticated(account),
+		keystroke_register: vec![Key::One],
+		balances: HashMap::from([(account, 5)]),
+		failed_attempts: 0,
+		locked_until: 0,
+		current_tick: 0,
 	};
 	let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
 	let expected = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticated,
+		cash_inside: 9,
+		auth: Auth::Waiting,
 		keystroke_register: Vec::new(),
+		balances: HashMap::from([(account, 4)]),
+		failed_attempts: 0,
+		locked_until: 0,
+		current_tick: 0,
 	};
 
 	assert_eq!(end, expected);
 }
 
 #[test]
-fn sm_3_enter_single_digit_of_withdraw_amount() {
+fn sm_3_cannot_overdraw_an_empty_account() {
+	let signer = keypair(1);
+	let pubkey = signer.verifying_key();
+	let account = account_id(&pubkey);
 	let start = Atm {
 		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticated,
-		keystroke_register: Vec::new(),
-	};
-	let end = Atm::next_state(&start, &Action::PressKey(Key::One));
-	let expected = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticated,
+		auth: Auth::Authenticated(account),
 		keystroke_register: vec![Key::One],
+		balances: HashMap::new(),
+		failed_attempts: 0,
+		locked_until: 0,
+		current_tick: 0,
 	};
+	let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
+	let expected = Atm { auth: Auth::Waiting, keystroke_register: Vec::new(), ..start };
 
 	assert_eq!(end, expected);
-
-	let start = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticated,
-		keystroke_register: vec![Key::One],
-	};
-	let end1 = Atm::next_state(&start, &Action::PressKey(Key::Four));
-	let expected1 = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticated,
-		keystroke_register: vec![Key::One, Key::Four],
-	};
-
-	assert_eq!(end1, expected1);
 }
 
 #[test]
-fn sm_3_try_to_withdraw_too_much() {
+fn sm_3_cross_card_isolation() {
+	let signer_a = keypair(1);
+	let signer_b = keypair(2);
+	let account_a = account_id(&signer_a.verifying_key());
+	let account_b = account_id(&signer_b.verifying_key());
+
 	let start = Atm {
-		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticated,
-		keystroke_register: vec![Key::One, Key::Four],
+		cash_inside: 100,
+		auth: Auth::Authenticated(account_a),
+		keystroke_register: vec![Key::One],
+		balances: HashMap::from([(account_a, 5), (account_b, 20)]),
+		failed_attempts: 0,
+		locked_until: 0,
+		current_tick: 0,
 	};
 	let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
-	let expected =
-		Atm { cash_inside: 10, expected_pin_hash: Auth::Waiting, keystroke_register: Vec::new() };
+	let expected = Atm {
+		cash_inside: 99,
+		auth: Auth::Waiting,
+		keystroke_register: Vec::new(),
+		balances: HashMap::from([(account_a, 4), (account_b, 20)]),
+		..start
+	};
 
 	assert_eq!(end, expected);
 }
 
 #[test]
-fn sm_3_withdraw_acceptable_amount() {
-	let start = Atm {
+fn sm_3_locked_out_atm_ignores_swipe_and_keys_until_enough_ticks_pass() {
+	let signer = keypair(1);
+	let pubkey = signer.verifying_key();
+	let locked = Atm {
 		cash_inside: 10,
-		expected_pin_hash: Auth::Authenticated,
-		keystroke_register: vec![Key::One],
+		auth: Auth::Waiting,
+		keystroke_register: Vec::new(),
+		balances: HashMap::new(),
+		failed_attempts: 1,
+		locked_until: 2,
+		current_tick: 0,
 	};
-	let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
-	let expected =
-		Atm { cash_inside: 9, expected_pin_hash: Auth::Waiting, keystroke_register: Vec::new() };
 
-	assert_eq!(end, expected);
+	let after_swipe = Atm::next_state(&locked, &Action::SwipeCard(pubkey));
+	assert_eq!(after_swipe, locked);
+
+	let after_key = Atm::next_state(&locked, &Action::PressKey(Key::One));
+	assert_eq!(after_key, locked);
+
+	let after_tick = Atm::next_state(&locked, &Action::Tick);
+	assert_eq!(after_tick.current_tick, 1);
+	// Still locked: 1 < 2.
+	let still_locked = Atm::next_state(&after_tick, &Action::SwipeCard(pubkey));
+	assert_eq!(still_locked, after_tick);
+
+	let unlocked = Atm::next_state(&after_tick, &Action::Tick);
+	assert_eq!(unlocked.current_tick, 2);
+	// Now unlocked: 2 < 2 is false, so the swipe goes through.
+	let end = Atm::next_state(&unlocked, &Action::SwipeCard(pubkey));
+	let nonce = unlocked.generate_nonce(&pubkey);
+	assert_eq!(end.auth, Auth::Challenged(nonce, pubkey));
 }
@@ -0,0 +1,226 @@
+//! A bidirectional payment channel between a customer and a merchant. The two parties fund
+//! the channel once on-chain, then exchange any number of balance updates off-chain before
+//! settling the final balances by closing the channel. Channels only ever move forward through
+//! `Open -> Established -> Closing -> Closed`; there is no way back.
+
+use super::StateMachine;
+
+/// The lifecycle phase of a payment channel.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Phase {
+	/// The channel has been created but not yet funded.
+	Open,
+	/// The channel is funded and open for off-chain payments.
+	Established,
+	/// One party has latched the final balances and is waiting to settle on-chain.
+	Closing,
+	/// The channel has settled; no further transitions are possible.
+	Closed,
+}
+
+/// Something you can do to a payment channel.
+pub enum Transition {
+	/// The customer deposits `amount` into the channel, moving it from `Open` to
+	/// `Established` and initializing the customer's balance to `amount`.
+	Fund(u64),
+	/// Shift `amount` from the customer's balance to the merchant's. A negative
+	/// amount shifts funds the other way, from merchant to customer.
+	Pay(i64),
+	/// Latch the current balances as the final settlement and stop accepting payments.
+	InitiateClose,
+	/// Settle the channel on-chain at its latched balances.
+	Finalize,
+}
+
+/// A bidirectional off-chain payment channel between a customer and a merchant.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PaymentChannel {
+	/// The customer's current balance in the channel.
+	cust_balance: u64,
+	/// The merchant's current balance in the channel.
+	merch_balance: u64,
+	/// Monotonically increasing count of payments applied, so the latest state can
+	/// always be told apart from a stale one.
+	seq: u64,
+	/// The channel's lifecycle phase.
+	phase: Phase,
+	/// The `(cust_balance, merch_balance)` latched by `InitiateClose` as the final
+	/// settlement. `None` until the channel starts closing.
+	settlement: Option<(u64, u64)>,
+}
+
+impl PaymentChannel {
+	/// A brand new, unfunded channel.
+	pub fn new() -> Self {
+		Self { cust_balance: 0, merch_balance: 0, seq: 0, phase: Phase::Open, settlement: None }
+	}
+}
+
+impl Default for PaymentChannel {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Apply a signed delta to a balance, refusing to let it go negative or overflow.
+fn apply_signed(balance: u64, delta: i64) -> Option<u64> {
+	if delta >= 0 { balance.checked_add(delta as u64) } else { balance.checked_sub(delta.unsigned_abs()) }
+}
+
+impl StateMachine for PaymentChannel {
+	type State = Self;
+	type Transition = Transition;
+
+	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+		use Transition::*;
+		let same_state = starting_state.clone();
+
+		match (starting_state.phase, t) {
+			(Phase::Open, Fund(amount)) => {
+				Self {
+					cust_balance: *amount,
+					merch_balance: 0,
+					phase: Phase::Established,
+					..same_state
+				}
+			},
+			(Phase::Established, Pay(amount)) => {
+				let new_cust = amount
+					.checked_neg()
+					.and_then(|neg_amount| apply_signed(starting_state.cust_balance, neg_amount));
+				let new_merch = apply_signed(starting_state.merch_balance, *amount);
+				match (new_cust, new_merch) {
+					(Some(cust_balance), Some(merch_balance)) => {
+						Self { cust_balance, merch_balance, seq: starting_state.seq + 1, ..same_state }
+					},
+					_ => same_state,
+				}
+			},
+			(Phase::Established, InitiateClose) => Self {
+				phase: Phase::Closing,
+				settlement: Some((starting_state.cust_balance, starting_state.merch_balance)),
+				..same_state
+			},
+			(Phase::Closing, Finalize) => Self { phase: Phase::Closed, ..same_state },
+			_ => same_state,
+		}
+	}
+}
+
+#[test]
+fn funding_moves_open_channel_to_established() {
+	let start = PaymentChannel::new();
+	let end = PaymentChannel::next_state(&start, &Transition::Fund(100));
+
+	let expected = PaymentChannel { cust_balance: 100, merch_balance: 0, seq: 0, phase: Phase::Established, settlement: None };
+	assert_eq!(end, expected);
+}
+
+#[test]
+fn funding_an_already_established_channel_is_a_no_op() {
+	let start = PaymentChannel { cust_balance: 100, merch_balance: 0, seq: 0, phase: Phase::Established, settlement: None };
+	let end = PaymentChannel::next_state(&start, &Transition::Fund(50));
+
+	assert_eq!(end, start);
+}
+
+#[test]
+fn paying_shifts_balance_from_customer_to_merchant_and_bumps_seq() {
+	let start = PaymentChannel { cust_balance: 100, merch_balance: 0, seq: 0, phase: Phase::Established, settlement: None };
+	let end = PaymentChannel::next_state(&start, &Transition::Pay(30));
+
+	let expected = PaymentChannel { cust_balance: 70, merch_balance: 30, seq: 1, phase: Phase::Established, settlement: None };
+	assert_eq!(end, expected);
+}
+
+#[test]
+fn a_negative_payment_refunds_from_merchant_to_customer() {
+	let start = PaymentChannel { cust_balance: 70, merch_balance: 30, seq: 1, phase: Phase::Established, settlement: None };
+	let end = PaymentChannel::next_state(&start, &Transition::Pay(-10));
+
+	let expected = PaymentChannel { cust_balance: 80, merch_balance: 20, seq: 2, phase: Phase::Established, settlement: None };
+	assert_eq!(end, expected);
+}
+
+#[test]
+fn payment_conserves_total_funds() {
+	let start = PaymentChannel { cust_balance: 100, merch_balance: 0, seq: 0, phase: Phase::Established, settlement: None };
+	let total_before = start.cust_balance + start.merch_balance;
+
+	let mid = PaymentChannel::next_state(&start, &Transition::Pay(40));
+	assert_eq!(mid.cust_balance + mid.merch_balance, total_before);
+
+	let end = PaymentChannel::next_state(&mid, &Transition::Pay(-15));
+	assert_eq!(end.cust_balance + end.merch_balance, total_before);
+}
+
+#[test]
+fn payment_that_would_overdraw_the_customer_is_rejected() {
+	let start = PaymentChannel { cust_balance: 10, merch_balance: 0, seq: 0, phase: Phase::Established, settlement: None };
+	let end = PaymentChannel::next_state(&start, &Transition::Pay(11));
+
+	assert_eq!(end, start);
+}
+
+#[test]
+fn payment_that_would_overdraw_the_merchant_is_rejected() {
+	let start = PaymentChannel { cust_balance: 10, merch_balance: 5, seq: 0, phase: Phase::Established, settlement: None };
+	let end = PaymentChannel::next_state(&start, &Transition::Pay(-6));
+
+	assert_eq!(end, start);
+}
+
+#[test]
+fn payment_of_i64_min_is_rejected_without_panicking() {
+	// `i64::MIN` has no positive counterpart, so negating it outright would overflow.
+	// It should be rejected like any other payment the customer can't cover.
+	let start = PaymentChannel { cust_balance: 10, merch_balance: 5, seq: 0, phase: Phase::Established, settlement: None };
+	let end = PaymentChannel::next_state(&start, &Transition::Pay(i64::MIN));
+
+	assert_eq!(end, start);
+}
+
+#[test]
+fn initiate_close_then_finalize_settles_the_channel() {
+	let established = PaymentChannel { cust_balance: 70, merch_balance: 30, seq: 1, phase: Phase::Established, settlement: None };
+	let closing = PaymentChannel::next_state(&established, &Transition::InitiateClose);
+	let expected_closing =
+		PaymentChannel { phase: Phase::Closing, settlement: Some((70, 30)), ..established };
+	assert_eq!(closing, expected_closing);
+
+	let closed = PaymentChannel::next_state(&closing, &Transition::Finalize);
+	assert_eq!(closed, PaymentChannel { phase: Phase::Closed, ..closing });
+}
+
+#[test]
+fn cannot_pay_once_closing_or_closed() {
+	let closing = PaymentChannel {
+		cust_balance: 70,
+		merch_balance: 30,
+		seq: 1,
+		phase: Phase::Closing,
+		settlement: Some((70, 30)),
+	};
+	let after_pay = PaymentChannel::next_state(&closing, &Transition::Pay(10));
+	assert_eq!(after_pay, closing);
+
+	let closed = PaymentChannel { phase: Phase::Closed, ..closing };
+	let after_pay = PaymentChannel::next_state(&closed, &Transition::Pay(10));
+	assert_eq!(after_pay, closed);
+}
+
+#[test]
+fn cannot_initiate_close_before_the_channel_is_established() {
+	let start = PaymentChannel::new();
+	let end = PaymentChannel::next_state(&start, &Transition::InitiateClose);
+
+	assert_eq!(end, start);
+}
+
+#[test]
+fn cannot_finalize_before_initiating_a_close() {
+	let start = PaymentChannel { cust_balance: 70, merch_balance: 30, seq: 1, phase: Phase::Established, settlement: None };
+	let end = PaymentChannel::next_state(&start, &Transition::Finalize);
+
+	assert_eq!(end, start);
+}
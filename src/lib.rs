@@ -0,0 +1,24 @@
+//! A collection of exercises that implement, from scratch, the core pieces of a blockchain.
+//! This crate holds the state machine exercises (`c1_state_machine`); it has no blockchain
+//! data structure or consensus modules.
+
+use std::hash::Hash;
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+pub mod c1_state_machine;
+
+/// A generic hash function usable by all the exercises in this crate.
+pub fn hash<T: Hash>(t: &T) -> u64 {
+	use std::collections::hash_map::DefaultHasher;
+	use std::hash::Hasher;
+
+	let mut s = DefaultHasher::new();
+	t.hash(&mut s);
+	s.finish()
+}
+
+/// Check that `signature` is a valid ed25519 signature over `message` under `public_key`.
+pub fn verify_signature(public_key: &VerifyingKey, message: &[u8], signature: &Signature) -> bool {
+	public_key.verify(message, signature).is_ok()
+}